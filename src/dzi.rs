@@ -0,0 +1,247 @@
+//! Deep Zoom (DZI) pyramid output.
+//!
+//! Writes an assembled [`image::DynamicImage`] as an OpenSeadragon-compatible
+//! Deep Zoom Image: a `<name>.dzi` descriptor next to a `<name>_files/<level>/<x>_<y>.jpg`
+//! tile tree, so huge scans can be viewed locally without loading the whole image at once.
+
+use std::fs;
+use std::path::Path;
+
+use image::{DynamicImage, GenericImageView};
+
+use crate::ZoomError;
+
+/// Side length, in pixels, of a single Deep Zoom tile.
+const TILE_SIZE: u32 = 256;
+
+/// Number of bytes per pixel in the raw RGBA8 buffers this module reads tiles from.
+const CHANNELS: u32 = 4;
+
+/// Assembles `image` into a Deep Zoom pyramid rooted at `outfile`
+/// (e.g. `foo.dzi`, with tiles written under `foo_files/`).
+pub fn save_dzi(image: &DynamicImage, outfile: &Path) -> Result<(), ZoomError> {
+    let (width, height) = image.dimensions();
+    let files_dir = files_dir_for(outfile);
+    let max_level = levels_for(width, height);
+
+    let (tiles_x, tiles_y) = write_native_level(image, &files_dir, max_level)?;
+    write_pyramid(&files_dir, max_level, width, height, tiles_x, tiles_y)?;
+    fs::write(outfile, descriptor(width, height))?;
+    Ok(())
+}
+
+/// Like [`save_dzi`], but reads tiles directly out of a flat RGBA8 buffer
+/// (e.g. a memory-mapped scratch file) instead of a [`DynamicImage`], so the
+/// low-memory assembler never has to materialize the whole image in RAM just
+/// to cut it into Deep Zoom tiles.
+pub fn save_dzi_from_rgba8(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    outfile: &Path,
+) -> Result<(), ZoomError> {
+    let files_dir = files_dir_for(outfile);
+    let max_level = levels_for(width, height);
+
+    let (tiles_x, tiles_y) =
+        write_native_level_from_rgba8(data, width, height, &files_dir, max_level)?;
+    write_pyramid(&files_dir, max_level, width, height, tiles_x, tiles_y)?;
+    fs::write(outfile, descriptor(width, height))?;
+    Ok(())
+}
+
+/// The `<name>_files/` directory a `.dzi` outfile's tiles are written under.
+fn files_dir_for(outfile: &Path) -> std::path::PathBuf {
+    let name = outfile
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "dezoomified".into());
+    outfile.with_file_name(format!("{}_files", name))
+}
+
+/// The native-resolution level number, per the Deep Zoom spec: `ceil(log2(max(width, height)))`,
+/// with level 0 being a single tile that downscales the whole image to ~1x1.
+fn levels_for(width: u32, height: u32) -> u32 {
+    let longest = width.max(height).max(1);
+    (longest as f64).log2().max(0.0).ceil() as u32
+}
+
+/// The width or height of `level`, given the corresponding full-resolution `full` dimension
+/// and the native-resolution `max_level`: each level coarser than the last halves it, rounding up.
+fn level_dimension(full: u32, max_level: u32, level: u32) -> u32 {
+    let shift = max_level - level;
+    (((full as f64) / 2f64.powi(shift as i32)).ceil() as u32).max(1)
+}
+
+/// Number of `TILE_SIZE` tiles needed to cover a level of size `dimension`.
+fn tiles_across(dimension: u32) -> u32 {
+    (dimension + TILE_SIZE - 1) / TILE_SIZE
+}
+
+fn descriptor(width: u32, height: u32) -> String {
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Image TileSize="{tile_size}" Overlap="0" Format="jpg" xmlns="http://schemas.microsoft.com/deepzoom/2008">
+    <Size Width="{width}" Height="{height}"/>
+</Image>
+"#,
+        tile_size = TILE_SIZE,
+        width = width,
+        height = height
+    )
+}
+
+/// Cuts `image` into `TILE_SIZE`x`TILE_SIZE` tiles at the highest (native resolution)
+/// pyramid level, handling the smaller edge tiles at the right/bottom borders.
+fn write_native_level(
+    image: &DynamicImage,
+    files_dir: &Path,
+    level: u32,
+) -> Result<(u32, u32), ZoomError> {
+    let level_dir = files_dir.join(level.to_string());
+    fs::create_dir_all(&level_dir)?;
+    let (width, height) = image.dimensions();
+    let tiles_x = tiles_across(width);
+    let tiles_y = tiles_across(height);
+    for y in 0..tiles_y {
+        for x in 0..tiles_x {
+            let tw = TILE_SIZE.min(width - x * TILE_SIZE);
+            let th = TILE_SIZE.min(height - y * TILE_SIZE);
+            image
+                .crop_imm(x * TILE_SIZE, y * TILE_SIZE, tw, th)
+                .save(level_dir.join(format!("{}_{}.jpg", x, y)))?;
+        }
+    }
+    Ok((tiles_x, tiles_y))
+}
+
+/// Like [`write_native_level`], but cuts tiles out of a flat RGBA8 buffer one
+/// at a time instead of a [`DynamicImage`], so the whole image is never
+/// materialized in memory at once: each tile only needs its own rows copied
+/// out of `data`.
+fn write_native_level_from_rgba8(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    files_dir: &Path,
+    level: u32,
+) -> Result<(u32, u32), ZoomError> {
+    let level_dir = files_dir.join(level.to_string());
+    fs::create_dir_all(&level_dir)?;
+    let tiles_x = tiles_across(width);
+    let tiles_y = tiles_across(height);
+    for y in 0..tiles_y {
+        for x in 0..tiles_x {
+            let tw = TILE_SIZE.min(width - x * TILE_SIZE);
+            let th = TILE_SIZE.min(height - y * TILE_SIZE);
+            let row_bytes = (tw * CHANNELS) as usize;
+            let mut buf = vec![0u8; row_bytes * th as usize];
+            for row in 0..th {
+                let src_y = y * TILE_SIZE + row;
+                let src_start = ((src_y as u64 * width as u64 + (x * TILE_SIZE) as u64)
+                    * CHANNELS as u64) as usize;
+                let dst_start = (row * tw * CHANNELS) as usize;
+                buf[dst_start..dst_start + row_bytes]
+                    .copy_from_slice(&data[src_start..src_start + row_bytes]);
+            }
+            let tile = image::RgbaImage::from_raw(tw, th, buf)
+                .expect("tile buffer sized to match tw x th");
+            DynamicImage::ImageRgba8(tile).save(level_dir.join(format!("{}_{}.jpg", x, y)))?;
+        }
+    }
+    Ok((tiles_x, tiles_y))
+}
+
+/// Builds every level coarser than `max_level` from its child level, following the
+/// minetest-tiler "unzoom" recurrence: each parent tile is assembled from the 2x2
+/// block of child tiles at (2x,2y), (2x+1,2y), (2x,2y+1), (2x+1,2y+1), each scaled
+/// to half its own size and pasted into its quadrant, then the assembled tile is
+/// cropped to the level's true (possibly ragged) edge dimensions. Missing child
+/// tiles (at the ragged edge of the pyramid) are left transparent.
+fn write_pyramid(
+    files_dir: &Path,
+    max_level: u32,
+    width: u32,
+    height: u32,
+    tiles_x: u32,
+    tiles_y: u32,
+) -> Result<(), ZoomError> {
+    let (mut child_tiles_x, mut child_tiles_y) = (tiles_x, tiles_y);
+    for level in (0..max_level).rev() {
+        let child_dir = files_dir.join((level + 1).to_string());
+        let parent_dir = files_dir.join(level.to_string());
+        fs::create_dir_all(&parent_dir)?;
+        let child_width = level_dimension(width, max_level, level + 1);
+        let child_height = level_dimension(height, max_level, level + 1);
+        let parent_width = level_dimension(width, max_level, level);
+        let parent_height = level_dimension(height, max_level, level);
+        let parent_tiles_x = tiles_across(parent_width);
+        let parent_tiles_y = tiles_across(parent_height);
+        for py in 0..parent_tiles_y {
+            for px in 0..parent_tiles_x {
+                let tw = TILE_SIZE.min(parent_width - px * TILE_SIZE);
+                let th = TILE_SIZE.min(parent_height - py * TILE_SIZE);
+                let parent = assemble_parent_tile(
+                    &child_dir,
+                    px,
+                    py,
+                    child_tiles_x,
+                    child_tiles_y,
+                    child_width,
+                    child_height,
+                    tw,
+                    th,
+                );
+                parent.save(parent_dir.join(format!("{}_{}.jpg", px, py)))?;
+            }
+        }
+        child_tiles_x = parent_tiles_x;
+        child_tiles_y = parent_tiles_y;
+    }
+    Ok(())
+}
+
+/// Combines the 2x2 block of child tiles rooted at `(2*px, 2*py)` into a single
+/// half-resolution parent tile of size `parent_width`x`parent_height`, skipping
+/// quadrants whose child tile is missing. Each child is downscaled by its own
+/// actual size (which may be smaller than `TILE_SIZE` at the ragged edge of the
+/// child level) rather than forced to a fixed half-tile size, so edge tiles
+/// aren't stretched.
+#[allow(clippy::too_many_arguments)]
+fn assemble_parent_tile(
+    child_dir: &Path,
+    px: u32,
+    py: u32,
+    child_tiles_x: u32,
+    child_tiles_y: u32,
+    child_width: u32,
+    child_height: u32,
+    parent_width: u32,
+    parent_height: u32,
+) -> DynamicImage {
+    let half = TILE_SIZE / 2;
+    let mut canvas = image::RgbaImage::new(TILE_SIZE, TILE_SIZE);
+    for (dx, dy) in &[(0u32, 0u32), (1, 0), (0, 1), (1, 1)] {
+        let cx = px * 2 + dx;
+        let cy = py * 2 + dy;
+        if cx >= child_tiles_x || cy >= child_tiles_y {
+            continue;
+        }
+        let child_tile_width = TILE_SIZE.min(child_width - cx * TILE_SIZE);
+        let child_tile_height = TILE_SIZE.min(child_height - cy * TILE_SIZE);
+        let child_path = child_dir.join(format!("{}_{}.jpg", cx, cy));
+        if let Ok(child) = image::open(&child_path) {
+            let scaled_w = ((child_tile_width + 1) / 2).max(1);
+            let scaled_h = ((child_tile_height + 1) / 2).max(1);
+            let scaled =
+                child.resize_exact(scaled_w, scaled_h, image::imageops::FilterType::Triangle);
+            image::imageops::overlay(
+                &mut canvas,
+                &scaled.to_rgba8(),
+                (dx * half) as i64,
+                (dy * half) as i64,
+            );
+        }
+    }
+    DynamicImage::ImageRgba8(canvas).crop_imm(0, 0, parent_width, parent_height)
+}
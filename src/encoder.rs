@@ -0,0 +1,193 @@
+//! Output format and quality selection.
+//!
+//! Previously the only control over the saved file was the extension of
+//! `outfile`, with `image`'s own format guessing picking the defaults. This
+//! lets callers choose the format explicitly (`--format`), with `--quality`
+//! mapped to the relevant encoder parameter, and falls back to the extension
+//! of `outfile` when `--format` isn't given.
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::str::FromStr;
+
+use image::{DynamicImage, ImageEncoder};
+
+use crate::ZoomError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    WebP,
+    Tiff,
+}
+
+impl FromStr for OutputFormat {
+    type Err = ZoomError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::WebP),
+            "tiff" | "tif" => Ok(OutputFormat::Tiff),
+            _ => Err(ZoomError::UnknownOutputFormat {
+                format: s.to_string(),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Tiff => "tiff",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl OutputFormat {
+    /// Guesses the format from `outfile`'s extension.
+    fn from_extension(outfile: &Path) -> Option<Self> {
+        outfile
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(|ext| OutputFormat::from_str(ext).ok())
+    }
+
+    /// The file extension conventionally used for this format.
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Tiff => "tiff",
+        }
+    }
+}
+
+/// Saves `image` to `outfile` in `format`, falling back to `outfile`'s
+/// extension when `format` is unset, and to `image`'s own format guessing
+/// when neither gives an answer. `quality` (0-100) is mapped to the relevant
+/// encoder parameter for lossy formats, and ignored for lossless ones. The
+/// assembled canvas is always RGBA (tiles are alpha-blended onto it) but
+/// opaque in practice, so JPEG is supported by dropping the alpha channel
+/// rather than rejected outright.
+pub fn save_image(
+    image: &DynamicImage,
+    outfile: &Path,
+    format: Option<OutputFormat>,
+    quality: Option<u8>,
+) -> Result<(), ZoomError> {
+    let format = match format.or_else(|| OutputFormat::from_extension(outfile)) {
+        Some(format) => format,
+        None => return Ok(image.save(outfile)?),
+    };
+
+    match format {
+        OutputFormat::Jpeg => {
+            let file = BufWriter::new(File::create(outfile)?);
+            let encoder =
+                image::codecs::jpeg::JpegEncoder::new_with_quality(file, quality.unwrap_or(90));
+            DynamicImage::ImageRgb8(image.to_rgb8()).write_with_encoder(encoder)?;
+        }
+        OutputFormat::Png => image.save_with_format(outfile, image::ImageFormat::Png)?,
+        OutputFormat::Tiff => image.save_with_format(outfile, image::ImageFormat::Tiff)?,
+        OutputFormat::WebP => save_webp(image, outfile, quality)?,
+    }
+    Ok(())
+}
+
+/// Encodes `image` as WebP: lossless when `quality` is unset or 100, lossy
+/// otherwise. The `image` crate doesn't encode WebP, so this goes through the
+/// `webp` crate directly.
+fn save_webp(image: &DynamicImage, outfile: &Path, quality: Option<u8>) -> Result<(), ZoomError> {
+    let rgba = image.to_rgba8();
+    save_rgba8_webp(&rgba, rgba.width(), rgba.height(), outfile, quality)
+}
+
+/// Saves a raw RGBA8 buffer directly, without building a [`DynamicImage`]
+/// first. Used by the low-memory assembler, whose backing buffer is already
+/// a flat RGBA8 memory-mapped file it would rather not copy out of. The
+/// low-memory canvas is always opaque in practice, so JPEG is supported by
+/// dropping the alpha channel rather than rejected outright.
+pub fn save_rgba8(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    outfile: &Path,
+    format: Option<OutputFormat>,
+    quality: Option<u8>,
+) -> Result<(), ZoomError> {
+    let format = match format.or_else(|| OutputFormat::from_extension(outfile)) {
+        Some(format) => format,
+        None => {
+            return Ok(image::save_buffer(
+                outfile,
+                data,
+                width,
+                height,
+                image::ColorType::Rgba8,
+            )?)
+        }
+    };
+    match format {
+        OutputFormat::Jpeg => {
+            let rgb = rgba8_to_rgb8(data);
+            let file = BufWriter::new(File::create(outfile)?);
+            let encoder =
+                image::codecs::jpeg::JpegEncoder::new_with_quality(file, quality.unwrap_or(90));
+            encoder.write_image(&rgb, width, height, image::ColorType::Rgb8)?;
+        }
+        OutputFormat::Png => {
+            let file = BufWriter::new(File::create(outfile)?);
+            image::codecs::png::PngEncoder::new(file).write_image(
+                data,
+                width,
+                height,
+                image::ColorType::Rgba8,
+            )?;
+        }
+        OutputFormat::Tiff => {
+            let file = BufWriter::new(File::create(outfile)?);
+            image::codecs::tiff::TiffEncoder::new(file).write_image(
+                data,
+                width,
+                height,
+                image::ColorType::Rgba8,
+            )?;
+        }
+        OutputFormat::WebP => save_rgba8_webp(data, width, height, outfile, quality)?,
+    }
+    Ok(())
+}
+
+/// Strips the alpha channel from a flat RGBA8 buffer, for encoders (JPEG)
+/// that can't store one.
+fn rgba8_to_rgb8(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(4)
+        .flat_map(|p| [p[0], p[1], p[2]])
+        .collect()
+}
+
+fn save_rgba8_webp(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    outfile: &Path,
+    quality: Option<u8>,
+) -> Result<(), ZoomError> {
+    let encoder = webp::Encoder::from_rgba(data, width, height);
+    let encoded = match quality {
+        Some(quality) if quality < 100 => encoder.encode(quality as f32),
+        _ => encoder.encode_lossless(),
+    };
+    std::fs::write(outfile, &*encoded)?;
+    Ok(())
+}
@@ -0,0 +1,143 @@
+//! Disk-backed tile assembly for images too large to comfortably fit in RAM.
+//!
+//! `Canvas` keeps the whole output image behind a `Mutex` in a single
+//! in-memory buffer, which for a multi-gigapixel scan can need many
+//! gigabytes before a single byte reaches disk. `LowMemoryCanvas` instead
+//! memory-maps a scratch file sized for the final image up front and has
+//! every tile write directly into its backing pages, so the OS can page
+//! parts of it back out under memory pressure instead of the process
+//! holding the whole thing as anonymous heap.
+
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+
+use image::GenericImageView;
+use memmap2::MmapMut;
+
+use crate::canvas::Tile;
+use crate::encoder::OutputFormat;
+use crate::vec2d::Vec2d;
+use crate::{dzi, encoder, ZoomError};
+
+/// Canvases at or above this many pixels use the disk-backed assembler by
+/// default, even without `--low-memory`, since they're unlikely to fit
+/// comfortably in RAM alongside everything else a browser/viewer needs.
+pub const LOW_MEMORY_THRESHOLD_PIXELS: u64 = 500_000_000; // ~500 megapixels
+
+const CHANNELS: u64 = 4;
+
+/// Assembles tiles directly into a memory-mapped RGBA8 scratch file the size
+/// of the final canvas, rather than an in-memory buffer.
+pub struct LowMemoryCanvas {
+    size: Vec2d,
+    mmap: MmapMut,
+    scratch_path: PathBuf,
+}
+
+impl LowMemoryCanvas {
+    /// Creates a memory-mapped scratch file sized for `size` next to `outfile`.
+    pub fn new(size: Vec2d, outfile: &Path) -> Result<Self, ZoomError> {
+        let scratch_path = outfile.with_extension("dezoomify-rs.scratch");
+        let byte_len = size.x as u64 * size.y as u64 * CHANNELS;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&scratch_path)?;
+        file.set_len(byte_len)?;
+        let mmap = unsafe { MmapMut::map_mut(&file)? };
+        Ok(LowMemoryCanvas {
+            size,
+            mmap,
+            scratch_path,
+        })
+    }
+
+    /// Alpha-blends `tile`'s pixels into the backing file at its position,
+    /// matching [`Canvas::add_tile`](crate::canvas::Canvas::add_tile)'s use of
+    /// `imageops::overlay` so both assemblers produce identical output for
+    /// dezoomers whose tiles overlap or carry real transparency.
+    pub fn add_tile(&mut self, tile: &Tile) -> Result<(), ZoomError> {
+        let (width, height) = tile.image.dimensions();
+        let (x0, y0) = (tile.position.x, tile.position.y);
+        if x0 + width > self.size.x || y0 + height > self.size.y {
+            return Err(ZoomError::TileCopyError {
+                x: x0,
+                y: y0,
+                twidth: width,
+                theight: height,
+                width: self.size.x,
+                height: self.size.y,
+            });
+        }
+        let rgba = tile.image.to_rgba8();
+        let row_bytes = (width * CHANNELS as u32) as usize;
+        for y in 0..height {
+            let src_row = &rgba.as_raw()[(y * width * CHANNELS as u32) as usize..][..row_bytes];
+            let offset = (((y0 + y) as u64 * self.size.x as u64 + x0 as u64) * CHANNELS) as usize;
+            let dst_row = &mut self.mmap[offset..offset + row_bytes];
+            for (dst_px, src_px) in dst_row.chunks_exact_mut(4).zip(src_row.chunks_exact(4)) {
+                blend_rgba8(dst_px, [src_px[0], src_px[1], src_px[2], src_px[3]]);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the assembled image as a Deep Zoom pyramid directly from the
+    /// memory-mapped buffer, tile by tile, rather than materializing the
+    /// whole image in RAM first.
+    pub fn save_dzi(self, outfile: &Path) -> Result<(), ZoomError> {
+        self.mmap.flush()?;
+        dzi::save_dzi_from_rgba8(&self.mmap, self.size.x, self.size.y, outfile)?;
+        let _ = std::fs::remove_file(&self.scratch_path);
+        Ok(())
+    }
+
+    /// Encodes the assembled image straight from the memory-mapped buffer
+    /// into `outfile`, then removes the scratch file.
+    pub fn save(
+        self,
+        outfile: &Path,
+        format: Option<OutputFormat>,
+        quality: Option<u8>,
+    ) -> Result<(), ZoomError> {
+        self.mmap.flush()?;
+        encoder::save_rgba8(
+            &self.mmap,
+            self.size.x,
+            self.size.y,
+            outfile,
+            format,
+            quality,
+        )?;
+        let _ = std::fs::remove_file(&self.scratch_path);
+        Ok(())
+    }
+}
+
+/// Alpha-composites straight (non-premultiplied) RGBA8 `src` over `dst` in
+/// place, using the standard "over" operator -- the same blend
+/// `image::imageops::overlay` performs per pixel, so overlapping or
+/// translucent tiles produce the same result on both assemblers.
+fn blend_rgba8(dst: &mut [u8], src: [u8; 4]) {
+    if src[3] == 0 {
+        return;
+    }
+    if src[3] == 255 {
+        dst.copy_from_slice(&src);
+        return;
+    }
+    let src_a = src[3] as f32 / 255.0;
+    let dst_a = dst[3] as f32 / 255.0;
+    let out_a = src_a + dst_a * (1.0 - src_a);
+    if out_a <= 0.0 {
+        dst.copy_from_slice(&[0, 0, 0, 0]);
+        return;
+    }
+    for c in 0..3 {
+        let blended = (src[c] as f32 * src_a + dst[c] as f32 * dst_a * (1.0 - src_a)) / out_a;
+        dst[c] = blended.round() as u8;
+    }
+    dst[3] = (out_a * 255.0).round() as u8;
+}
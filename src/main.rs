@@ -4,7 +4,7 @@ use std::fs;
 use std::io::{BufRead, Read};
 use std::sync::Mutex;
 
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use itertools::Itertools;
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
@@ -15,6 +15,9 @@ use canvas::{Canvas, Tile};
 use custom_error::custom_error;
 use dezoomer::TileReference;
 use dezoomer::{Dezoomer, DezoomerError, DezoomerInput, ZoomLevels};
+use encoder::OutputFormat;
+use host_limiter::{host_of, HostLimiter};
+use low_memory_canvas::{LowMemoryCanvas, LOW_MEMORY_THRESHOLD_PIXELS};
 pub use vec2d::Vec2d;
 
 use crate::dezoomer::ZoomLevel;
@@ -23,8 +26,13 @@ mod auto;
 mod canvas;
 mod custom_yaml;
 mod dezoomer;
+mod dzi;
+mod encoder;
 mod google_arts_and_culture;
+mod host_limiter;
 mod iiif;
+mod low_memory_canvas;
+mod retry;
 mod vec2d;
 mod zoomify;
 
@@ -33,10 +41,22 @@ struct Arguments {
     /// Input URL or local file name
     input_uri: Option<String>,
 
-    /// File to which the resulting image should be saved
+    /// File to which the resulting image should be saved. If the extension is
+    /// `.dzi`, a Deep Zoom (DZI) tile pyramid is written instead of a single image.
+    /// Ignored in batch mode (see `--input-uri` and `--input-file`), where each
+    /// input gets its own output file.
     #[structopt(default_value = "dezoomified.jpg")]
     outfile: std::path::PathBuf,
 
+    /// Additional input to dezoom, for batch mode. May be repeated. Combined with
+    /// `input_uri` and the contents of `--input-file`, if any.
+    #[structopt(short = "i", long = "input-uri")]
+    extra_input_uris: Vec<String>,
+
+    /// File listing one input per line (`uri` or `uri outfile`), for batch mode.
+    #[structopt(long = "input-file")]
+    input_file: Option<std::path::PathBuf>,
+
     /// Name of the dezoomer to use
     #[structopt(short = "d", long = "dezoomer", default_value = "auto")]
     dezoomer: String,
@@ -59,6 +79,38 @@ struct Arguments {
     /// tiles will be downloaded at the same time.
     #[structopt(short = "n", long = "num-threads")]
     num_threads: Option<usize>,
+
+    /// Maximum number of concurrent requests to a single host, regardless of the
+    /// overall parallelism. Helps avoid anti-DDoS bans on servers that only host
+    /// a single image.
+    #[structopt(long = "max-per-host")]
+    max_per_host: Option<usize>,
+
+    /// Minimum delay, in milliseconds, to leave between two requests to the same host.
+    #[structopt(long = "throttle")]
+    throttle: Option<u64>,
+
+    /// Number of times to retry a tile download after a retryable error
+    /// (timeout or other network failure, HTTP 429 or 5xx), with exponential
+    /// backoff between attempts.
+    #[structopt(long = "retries", default_value = "3")]
+    retries: u32,
+
+    /// Output format (jpeg, png, webp or tiff). Defaults to guessing from
+    /// outfile's extension.
+    #[structopt(long = "format")]
+    format: Option<OutputFormat>,
+
+    /// Quality, from 0 to 100, of the output image. Only applies to lossy
+    /// formats (jpeg, webp); ignored otherwise.
+    #[structopt(long = "quality")]
+    quality: Option<u8>,
+
+    /// Assemble tiles on disk instead of in memory, to support images that
+    /// don't fit in RAM. Used automatically above a size threshold even when
+    /// this flag isn't set.
+    #[structopt(long = "low-memory")]
+    low_memory: bool,
 }
 
 impl Arguments {
@@ -206,25 +258,189 @@ fn progress_bar(n: usize) -> ProgressBar {
     let progress = ProgressBar::new(n as u64);
     progress.set_style(
         ProgressStyle::default_bar()
-            .template("[ETA:{eta}] {bar:40.cyan/blue} {pos:>4}/{len:4} {msg}")
+            .template("[ETA:{eta}] {prefix} {bar:40.cyan/blue} {pos:>4}/{len:4} {msg}")
             .progress_chars("##-"),
     );
     progress
 }
 
-fn find_zoomlevel(args: &Arguments) -> Result<ZoomLevel, ZoomError> {
+fn find_zoomlevel(args: &Arguments, uri: &str) -> Result<ZoomLevel, ZoomError> {
     let mut dezoomer = args.find_dezoomer()?;
-    let uri = args.choose_input_uri();
     let http_client = client(HashMap::new())?;
     println!("Trying to locate a zoomable image...");
-    let zoom_levels: Vec<ZoomLevel> = list_tiles(dezoomer.as_mut(), &http_client, &uri)?;
+    let zoom_levels: Vec<ZoomLevel> = list_tiles(dezoomer.as_mut(), &http_client, uri)?;
     choose_level(zoom_levels, args)
 }
 
+/// One input/output pair to dezoom, as collected from the CLI positional
+/// argument, `--input-uri` flags, and/or an `--input-file`.
+struct BatchItem {
+    uri: String,
+    outfile: std::path::PathBuf,
+}
+
+/// Gathers every input to process. A single legacy `input_uri` (with no other
+/// batch inputs) keeps using `args.outfile` verbatim; every other input gets a
+/// generated output file name, unless `--input-file` specifies one explicitly.
+fn collect_batch_items(args: &Arguments) -> Result<Vec<BatchItem>, ZoomError> {
+    let mut items = Vec::new();
+
+    if let Some(input_file) = &args.input_file {
+        let contents = fs::read_to_string(input_file)?;
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let uri = parts.next().unwrap_or(line).to_string();
+            let outfile = match parts.next().map(str::trim).filter(|s| !s.is_empty()) {
+                Some(name) => std::path::PathBuf::from(name),
+                None => default_batch_outfile(&uri, items.len(), args.format),
+            };
+            items.push(BatchItem { uri, outfile });
+        }
+    }
+
+    for uri in &args.extra_input_uris {
+        let outfile = default_batch_outfile(uri, items.len(), args.format);
+        items.push(BatchItem {
+            uri: uri.clone(),
+            outfile,
+        });
+    }
+
+    if let Some(uri) = &args.input_uri {
+        let outfile = if items.is_empty() {
+            args.outfile.clone()
+        } else {
+            default_batch_outfile(uri, items.len(), args.format)
+        };
+        items.insert(
+            0,
+            BatchItem {
+                uri: uri.clone(),
+                outfile,
+            },
+        );
+    }
+
+    Ok(items)
+}
+
+/// Generates an output file name for a batch input that wasn't given one
+/// explicitly, derived from the last path segment of its URI and given the
+/// extension for `format` (or `.jpg`, the default format, when unset).
+fn default_batch_outfile(
+    uri: &str,
+    index: usize,
+    format: Option<OutputFormat>,
+) -> std::path::PathBuf {
+    let stem = uri
+        .trim_end_matches('/')
+        .rsplit('/')
+        .next()
+        .filter(|s| !s.is_empty())
+        .unwrap_or("dezoomified");
+    let sanitized: String = stem
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    let extension = format.map(OutputFormat::extension).unwrap_or("jpg");
+    std::path::PathBuf::from(format!("{}_{}.{}", sanitized, index, extension))
+}
+
 fn dezoomify(args: Arguments) -> Result<(), ZoomError> {
     initialize_threadpool(&args);
-    let zoom_level = find_zoomlevel(&args)?;
-    println!("Dezooming {}", zoom_level.name());
+    let host_limiter = HostLimiter::new(
+        args.max_per_host,
+        args.throttle.map(std::time::Duration::from_millis),
+    );
+
+    let mut items = collect_batch_items(&args)?;
+    if items.is_empty() {
+        items.push(BatchItem {
+            uri: args.choose_input_uri(),
+            outfile: args.outfile.clone(),
+        });
+    }
+
+    if items.len() == 1 {
+        let item = items.remove(0);
+        let progress = progress_bar(0);
+        dezoomify_one(&args, &item.uri, &item.outfile, &progress, &host_limiter)
+    } else {
+        dezoomify_batch(&args, items, &host_limiter)
+    }
+}
+
+/// Runs every batch item concurrently, each with its own progress bar under a
+/// shared [`MultiProgress`] display plus an overall bar, while still respecting
+/// the per-host and thread limits shared across the whole batch.
+fn dezoomify_batch(
+    args: &Arguments,
+    items: Vec<BatchItem>,
+    host_limiter: &HostLimiter,
+) -> Result<(), ZoomError> {
+    let multi = MultiProgress::new();
+    let overall = multi.add(progress_bar(items.len()));
+    overall.set_message("images processed");
+
+    let mut results: Vec<(String, Result<(), ZoomError>)> = Vec::new();
+    rayon::scope(|scope| {
+        scope.spawn(|_| {
+            results = items
+                .into_par_iter()
+                .map(|item| {
+                    let progress = multi.add(progress_bar(0));
+                    progress.set_prefix(item.uri.clone());
+                    let result =
+                        dezoomify_one(args, &item.uri, &item.outfile, &progress, host_limiter);
+                    if let Err(e) = &result {
+                        progress.finish_with_message(&format!("Failed: {}", e));
+                    }
+                    overall.inc(1);
+                    (item.uri, result)
+                })
+                .collect::<Vec<_>>();
+            // multi.join() below blocks until every bar it's tracking is
+            // finished, including `overall` itself; finish it here, inside
+            // the spawned task, so that condition can actually be met
+            // instead of the scope and the join waiting on each other forever.
+            overall.finish_with_message("Done processing the batch.");
+        });
+        multi.join().expect("failed to draw progress bars");
+    });
+
+    for (uri, result) in &results {
+        if let Err(e) = result {
+            eprintln!("Error processing {}: {}", uri, e);
+        }
+    }
+
+    if results.iter().all(|(_, r)| r.is_err()) {
+        return Err(ZoomError::NoTile);
+    }
+    Ok(())
+}
+
+/// Dezooms a single image: locates a zoom level, downloads its tiles onto
+/// `progress`, and saves the result to `outfile`.
+fn dezoomify_one(
+    args: &Arguments,
+    uri: &str,
+    outfile: &std::path::Path,
+    progress: &ProgressBar,
+    host_limiter: &HostLimiter,
+) -> Result<(), ZoomError> {
+    let zoom_level = find_zoomlevel(args, uri)?;
+    progress.println(format!("Dezooming {}", zoom_level.name()));
 
     let http_client = client(zoom_level.http_headers())?;
 
@@ -234,26 +450,22 @@ fn dezoomify(args: Arguments) -> Result<(), ZoomError> {
         .filter_map(display_err)
         .collect();
 
-    let progress = progress_bar(tile_refs.len());
+    progress.set_length(tile_refs.len() as u64);
     let total_tiles = tile_refs.len();
 
-    let canvas = Mutex::new(Canvas::new(zoom_level.size_hint()));
+    let assembler = Assembler::new(zoom_level.size_hint(), outfile, args.low_memory)?;
 
     let successful_tiles = tile_refs
         .into_par_iter()
         .flat_map(|tile_ref: TileReference| {
             progress.inc(1);
             progress.set_message(&format!("Downloading tile at {}", tile_ref.position));
-            Tile::download(&zoom_level, &tile_ref, &http_client)
-                .map_err(|e| ZoomError::TileDownloadError {
-                    uri: tile_ref.url.clone(),
-                    cause: e.into(),
-                })
-                .and_then(|tile| canvas.lock().unwrap().add_tile(&tile))
+            let _permit = host_limiter.acquire(&host_of(&tile_ref.url));
+            download_tile_with_retries(&zoom_level, &tile_ref, &http_client, args.retries)
+                .and_then(|tile| assembler.add_tile(&tile))
                 .ok()
         })
         .count();
-    let canvas = canvas.into_inner().unwrap();
     let final_msg = if successful_tiles == total_tiles {
         "Downloaded all tiles.".into()
     } else if successful_tiles > 0 {
@@ -266,17 +478,147 @@ fn dezoomify(args: Arguments) -> Result<(), ZoomError> {
     };
     progress.finish_with_message(&final_msg);
 
-    println!("Saving the image to {}...", &args.outfile.to_string_lossy());
-    canvas.image().save(&args.outfile)?;
-    println!(
+    progress.println(format!("Saving the image to {}...", outfile.to_string_lossy()));
+    assembler.save(outfile, args.format, args.quality)?;
+    progress.println(format!(
         "Saved the image to {}",
-        fs::canonicalize(&args.outfile)
-            .unwrap_or(args.outfile)
+        fs::canonicalize(outfile)
+            .unwrap_or_else(|_| outfile.to_path_buf())
             .to_string_lossy()
-    );
+    ));
     Ok(())
 }
 
+/// Where downloaded tiles get assembled: entirely in memory (the fast path
+/// for images that comfortably fit in RAM), or backed by a memory-mapped
+/// scratch file on disk for images that don't, selected by [`Assembler::new`]
+/// from the zoom level's size hint or the explicit `--low-memory` flag.
+enum Assembler {
+    InMemory(Mutex<Canvas>),
+    LowMemory(Mutex<LowMemoryCanvas>),
+}
+
+impl Assembler {
+    fn new(
+        size_hint: Option<Vec2d>,
+        outfile: &std::path::Path,
+        low_memory: bool,
+    ) -> Result<Self, ZoomError> {
+        let over_threshold = size_hint
+            .as_ref()
+            .map(|s| s.x as u64 * s.y as u64 >= LOW_MEMORY_THRESHOLD_PIXELS)
+            .unwrap_or(false);
+        if low_memory || over_threshold {
+            match size_hint {
+                Some(size) => {
+                    return Ok(Assembler::LowMemory(Mutex::new(LowMemoryCanvas::new(
+                        size, outfile,
+                    )?)))
+                }
+                None => eprintln!(
+                    "Warning: low-memory assembly needs a known image size; \
+                     assembling in memory instead."
+                ),
+            }
+        }
+        Ok(Assembler::InMemory(Mutex::new(Canvas::new(size_hint))))
+    }
+
+    fn add_tile(&self, tile: &Tile) -> Result<(), ZoomError> {
+        match self {
+            Assembler::InMemory(canvas) => canvas.lock().unwrap().add_tile(tile),
+            Assembler::LowMemory(canvas) => canvas.lock().unwrap().add_tile(tile),
+        }
+    }
+
+    fn save(
+        self,
+        outfile: &std::path::Path,
+        format: Option<OutputFormat>,
+        quality: Option<u8>,
+    ) -> Result<(), ZoomError> {
+        match self {
+            Assembler::InMemory(canvas) => {
+                let image = canvas.into_inner().unwrap().image();
+                if is_dzi_outfile(outfile) {
+                    dzi::save_dzi(&image, outfile)
+                } else {
+                    encoder::save_image(&image, outfile, format, quality)
+                }
+            }
+            Assembler::LowMemory(canvas) => {
+                let canvas = canvas.into_inner().unwrap();
+                if is_dzi_outfile(outfile) {
+                    canvas.save_dzi(outfile)
+                } else {
+                    canvas.save(outfile, format, quality)
+                }
+            }
+        }
+    }
+}
+
+/// Whether `outfile` asks for a Deep Zoom pyramid rather than a single flattened image.
+fn is_dzi_outfile(outfile: &std::path::Path) -> bool {
+    outfile
+        .extension()
+        .map(|ext| ext.eq_ignore_ascii_case("dzi"))
+        .unwrap_or(false)
+}
+
+/// Downloads a single tile, retrying retryable errors (timeouts, HTTP 429/5xx)
+/// up to `max_retries` times with exponential backoff. Permanent errors (e.g. a
+/// 404) are returned immediately without retrying.
+fn download_tile_with_retries(
+    zoom_level: &ZoomLevel,
+    tile_ref: &TileReference,
+    http_client: &Client,
+    max_retries: u32,
+) -> Result<Tile, ZoomError> {
+    let mut attempt = 0;
+    loop {
+        match Tile::download(zoom_level, tile_ref, http_client) {
+            Ok(tile) => return Ok(tile),
+            Err(e) if attempt < max_retries && is_retryable(&e) => {
+                attempt += 1;
+                std::thread::sleep(retry::backoff_delay(attempt));
+            }
+            Err(e) => {
+                return Err(ZoomError::TileDownloadError {
+                    uri: tile_ref.url.clone(),
+                    cause: e.into(),
+                })
+            }
+        }
+    }
+}
+
+/// Whether a tile download error is transient and worth retrying: network
+/// errors with no status code (timeouts, failed connections) and HTTP
+/// 429/5xx responses. A 404 or other client
+/// error is treated as permanent. Checks both `ZoomError::Networking` directly
+/// and a `reqwest::Error` wrapped inside `ZoomError::PostProcessing`, since
+/// some dezoomers surface a failed tile fetch through their own post-processing
+/// step rather than the plain networking path.
+fn is_retryable(err: &ZoomError) -> bool {
+    match reqwest_error(err) {
+        Some(source) => source
+            .status()
+            .map(|status| status.is_server_error() || status.as_u16() == 429)
+            .unwrap_or(true),
+        None => false,
+    }
+}
+
+/// Extracts the underlying `reqwest::Error`, if any, from a tile download error.
+fn reqwest_error(err: &ZoomError) -> Option<&reqwest::Error> {
+    match err {
+        ZoomError::Networking { source } => Some(source),
+        ZoomError::PostProcessing { source } => source.downcast_ref::<reqwest::Error>(),
+        _ => None,
+    }
+}
+
 fn client(headers: HashMap<String, String>) -> Result<reqwest::Client, ZoomError> {
     let header_map: Result<header::HeaderMap, ZoomError> = default_headers()
         .iter()
@@ -320,4 +662,58 @@ custom_error! {
     NoSuchDezoomer{name: String} = "No such dezoomer: {name}",
     InvalidHeaderName{source: header::InvalidHeaderName} = "Invalid header name: {source}",
     InvalidHeaderValue{source: header::InvalidHeaderValue} = "Invalid header value: {source}",
+    UnknownOutputFormat{format: String} = "Unknown output format: '{format}'. \
+                                           Expected one of: jpeg, png, webp, tiff",
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpListener;
+
+    /// Starts a one-shot HTTP server on a local ephemeral port that replies to a
+    /// single request with `status_line` (e.g. `"HTTP/1.1 503 Service Unavailable"`),
+    /// and returns its URL.
+    fn serve_once(status_line: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let _ = stream
+                    .write_all(format!("{status_line}\r\nContent-Length: 0\r\n\r\n").as_bytes());
+            }
+        });
+        format!("http://{}/", addr)
+    }
+
+    fn networking_error(status_line: &'static str) -> ZoomError {
+        let url = serve_once(status_line);
+        let error = Client::new()
+            .get(&url)
+            .send()
+            .unwrap()
+            .error_for_status()
+            .unwrap_err();
+        ZoomError::Networking { source: error }
+    }
+
+    #[test]
+    fn server_error_is_retried() {
+        assert!(is_retryable(&networking_error(
+            "HTTP/1.1 503 Service Unavailable"
+        )));
+    }
+
+    #[test]
+    fn too_many_requests_is_retried() {
+        assert!(is_retryable(&networking_error(
+            "HTTP/1.1 429 Too Many Requests"
+        )));
+    }
+
+    #[test]
+    fn not_found_is_not_retried() {
+        assert!(!is_retryable(&networking_error("HTTP/1.1 404 Not Found")));
+    }
 }
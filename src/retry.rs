@@ -0,0 +1,37 @@
+//! Exponential backoff helpers for retrying failed tile downloads.
+
+use std::time::Duration;
+
+/// Delay before the first retry; doubled on each subsequent attempt.
+const BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// Computes the delay to wait before retry attempt number `attempt` (1-based),
+/// doubling the base delay each attempt and adding up to 50% jitter so that many
+/// tiles retrying at once don't all hammer the server in lockstep.
+pub fn backoff_delay(attempt: u32) -> Duration {
+    let exponential = BASE_DELAY * 2u32.saturating_pow(attempt.saturating_sub(1));
+    let jitter_fraction = (pseudo_random(attempt) % 50) as f64 / 100.0;
+    exponential.mul_f64(1.0 + jitter_fraction)
+}
+
+/// A cheap, dependency-free source of per-call pseudo-randomness, good enough to
+/// spread out retries. Not meant to be cryptographically meaningful.
+fn pseudo_random(seed: u32) -> u32 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    nanos.wrapping_mul(2_654_435_761).wrapping_add(seed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delay_grows_with_attempt() {
+        assert!(backoff_delay(3) >= BASE_DELAY * 4);
+        assert!(backoff_delay(1) >= BASE_DELAY);
+    }
+}